@@ -0,0 +1,19 @@
+//! Crate-wide error type.
+
+use std::fmt;
+
+/// Errors that can occur across the validated-streams services.
+#[derive(Debug)]
+pub enum Error {
+	/// catch-all wrapping the stringified source error, used for failures coming from the
+	/// runtime API, the network, or the storage backend
+	Other(String),
+}
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Error::Other(msg) => write!(f, "{}", msg),
+		}
+	}
+}
+impl std::error::Error for Error {}
@@ -0,0 +1,5 @@
+//! Validated streams: witness event proofs into block import and finalization.
+
+pub mod errors;
+pub mod proofs;
+pub mod services;
@@ -0,0 +1,133 @@
+//! Storage backend for collected event proofs, and for the bookkeeping
+//! [DefferedBlocks](crate::services::witness_block_import::DefferedBlocks) needs to survive a
+//! restart -- both are kept in the same embedded store since they're both small, write-heavy,
+//! node-local tables.
+
+use crate::errors::Error;
+use node_runtime::opaque::Block;
+use sp_core::H256;
+use sp_runtime::{app_crypto::CryptoTypePublicPair, traits::NumberFor};
+use std::{collections::HashMap, path::Path};
+
+/// signatures collected so far for a set of events, keyed by event id then by the signing
+/// authority's public key
+pub type ProofsMap = HashMap<H256, HashMap<CryptoTypePublicPair, Vec<u8>>>;
+
+/// storage backend behind the event-witnessing pipeline: the event proofs themselves, the
+/// block-hash -> events index used to serve whole-block proof requests, and the deferred-block
+/// bookkeeping needed to survive a restart.
+pub trait EventProofs {
+	/// merge `proofs` into the store
+	fn add_events_proofs(&self, proofs: ProofsMap) -> Result<(), Error>;
+	/// fetch whatever proofs are currently known for `events`
+	fn get_events_proofs(&self, events: &[H256]) -> Result<ProofsMap, Error>;
+	/// record that `block_hash` contains `events`, so [Self::get_block_proofs] can later serve
+	/// their proofs as a whole to a peer asking for that block
+	fn record_block_events(&self, block_hash: &H256, events: &[H256]) -> Result<(), Error>;
+	/// fetch the proofs of every event recorded against `block_hash` via
+	/// [Self::record_block_events]
+	fn get_block_proofs(&self, block_hash: &H256) -> Result<ProofsMap, Error>;
+	/// persist a block still awaiting proofs, so it can be reloaded and retried after a restart
+	fn add_deffered_block(
+		&self,
+		block_hash: &H256,
+		block_number: NumberFor<Block>,
+		unwitnessed_events: &[H256],
+	) -> Result<(), Error>;
+	/// load every block still awaiting proofs, persisted via [Self::add_deffered_block]
+	fn get_deffered_blocks(&self) -> Result<Vec<(H256, (NumberFor<Block>, Vec<H256>))>, Error>;
+	/// drop a deferred block once its proofs have been found
+	fn remove_deffered_block(&self, block_hash: &H256) -> Result<(), Error>;
+}
+
+/// [EventProofs] backed by a [sled] database, one tree per table.
+pub struct SledEventProofs {
+	db: sled::Db,
+}
+impl SledEventProofs {
+	/// Open (or create) a [SledEventProofs] store at `path`
+	pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+		let db = sled::open(path).map_err(|e| Error::Other(e.to_string()))?;
+		Ok(Self { db })
+	}
+	fn tree(&self, name: &str) -> Result<sled::Tree, Error> {
+		self.db.open_tree(name).map_err(|e| Error::Other(e.to_string()))
+	}
+}
+impl EventProofs for SledEventProofs {
+	fn add_events_proofs(&self, proofs: ProofsMap) -> Result<(), Error> {
+		let tree = self.tree("event_proofs")?;
+		for (event, proof) in proofs {
+			let mut merged: HashMap<CryptoTypePublicPair, Vec<u8>> = tree
+				.get(event.as_bytes())
+				.map_err(|e| Error::Other(e.to_string()))?
+				.and_then(|bytes| bincode::deserialize(&bytes).ok())
+				.unwrap_or_default();
+			merged.extend(proof);
+			let encoded = bincode::serialize(&merged).map_err(|e| Error::Other(e.to_string()))?;
+			tree.insert(event.as_bytes(), encoded).map_err(|e| Error::Other(e.to_string()))?;
+		}
+		Ok(())
+	}
+	fn get_events_proofs(&self, events: &[H256]) -> Result<ProofsMap, Error> {
+		let tree = self.tree("event_proofs")?;
+		let mut proofs = ProofsMap::new();
+		for event in events {
+			if let Some(bytes) =
+				tree.get(event.as_bytes()).map_err(|e| Error::Other(e.to_string()))?
+			{
+				let proof =
+					bincode::deserialize(&bytes).map_err(|e| Error::Other(e.to_string()))?;
+				proofs.insert(*event, proof);
+			}
+		}
+		Ok(proofs)
+	}
+	fn record_block_events(&self, block_hash: &H256, events: &[H256]) -> Result<(), Error> {
+		let tree = self.tree("block_events")?;
+		let encoded =
+			bincode::serialize(&events.to_vec()).map_err(|e| Error::Other(e.to_string()))?;
+		tree.insert(block_hash.as_bytes(), encoded).map_err(|e| Error::Other(e.to_string()))?;
+		Ok(())
+	}
+	fn get_block_proofs(&self, block_hash: &H256) -> Result<ProofsMap, Error> {
+		let tree = self.tree("block_events")?;
+		let events: Vec<H256> = match tree
+			.get(block_hash.as_bytes())
+			.map_err(|e| Error::Other(e.to_string()))?
+		{
+			Some(bytes) => bincode::deserialize(&bytes).map_err(|e| Error::Other(e.to_string()))?,
+			None => return Ok(ProofsMap::new()),
+		};
+		self.get_events_proofs(&events)
+	}
+	fn add_deffered_block(
+		&self,
+		block_hash: &H256,
+		block_number: NumberFor<Block>,
+		unwitnessed_events: &[H256],
+	) -> Result<(), Error> {
+		let tree = self.tree("deffered_blocks")?;
+		let encoded = bincode::serialize(&(block_number, unwitnessed_events.to_vec()))
+			.map_err(|e| Error::Other(e.to_string()))?;
+		tree.insert(block_hash.as_bytes(), encoded).map_err(|e| Error::Other(e.to_string()))?;
+		Ok(())
+	}
+	fn get_deffered_blocks(&self) -> Result<Vec<(H256, (NumberFor<Block>, Vec<H256>))>, Error> {
+		let tree = self.tree("deffered_blocks")?;
+		tree.iter()
+			.map(|entry| {
+				let (key, value) = entry.map_err(|e| Error::Other(e.to_string()))?;
+				let block_hash = H256::from_slice(&key);
+				let decoded =
+					bincode::deserialize(&value).map_err(|e| Error::Other(e.to_string()))?;
+				Ok((block_hash, decoded))
+			})
+			.collect()
+	}
+	fn remove_deffered_block(&self, block_hash: &H256) -> Result<(), Error> {
+		let tree = self.tree("deffered_blocks")?;
+		tree.remove(block_hash.as_bytes()).map_err(|e| Error::Other(e.to_string()))?;
+		Ok(())
+	}
+}
@@ -0,0 +1,5 @@
+//! Node-facing services built on top of the event proofs storage and runtime.
+
+pub mod events;
+pub mod proof_provider;
+pub mod witness_block_import;
@@ -0,0 +1,342 @@
+//! Transports used to fetch the event proofs of a deferred block from, and advertise them to,
+//! the rest of the network.
+
+use crate::proofs::ProofsMap;
+use async_trait::async_trait;
+use futures::channel::oneshot;
+use node_runtime::opaque::Block;
+use sc_network::{
+	request_responses::{IncomingRequest, OutgoingResponse, ProtocolConfig},
+	IfDisconnected, KademliaKey, NetworkDHTProvider, NetworkService, PeerId,
+};
+use sp_core::H256;
+use sp_runtime::traits::NumberFor;
+use std::{borrow::Cow, collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+
+/// Name of the [ProtocolConfig] registered for [RequestResponseProofProvider].
+pub const PROOF_REQUEST_PROTOCOL_NAME: &str = "/validated-streams/proofs/1";
+
+/// Transport used by [DefferedBlocks](crate::services::witness_block_import::DefferedBlocks) to
+/// retreive the proofs of the events included in a deferred block, and by [WitnessBlockImport
+/// ](crate::services::witness_block_import::WitnessBlockImport) to advertise the proofs of a
+/// freshly imported block to the rest of the network. Implementations may be stacked by trying
+/// several in turn.
+#[async_trait]
+pub trait ProofProvider {
+	/// ask the network for the proofs of `events`, included in the block identified by
+	/// `block_hash`
+	async fn request_proofs(&self, block_hash: H256, events: &[H256]);
+	/// make this node's own copy of `proofs` available to peers requesting `block_hash`
+	async fn provide_proofs(&self, block_hash: H256, proofs: ProofsMap);
+}
+
+/// candidate peers to ask for a block's proofs, given its recorded `announcer` (if any) and the
+/// set of currently `connected` peers: prefer the announcer, otherwise fall back to broadcasting
+/// to everyone connected. Split out of [RequestResponseProofProvider::candidate_peers] so the
+/// selection logic can be tested without a live [NetworkService].
+fn candidate_peers_from(announcer: Option<PeerId>, connected: impl Iterator<Item = PeerId>) -> Vec<PeerId> {
+	match announcer {
+		Some(peer) => vec![peer],
+		None => connected.collect(),
+	}
+}
+
+/// try `peers` in order, calling `try_peer` on each, stopping at (and returning `true` for) the
+/// first one it reports success for. Split out of [RequestResponseProofProvider::request_proofs]
+/// so the stop-at-first-success behavior can be tested without a live [NetworkService].
+async fn try_peers_until_success<F, Fut>(peers: Vec<PeerId>, mut try_peer: F) -> bool
+where
+	F: FnMut(PeerId) -> Fut,
+	Fut: std::future::Future<Output = bool>,
+{
+	for peer in peers {
+		if try_peer(peer).await {
+			return true
+		}
+	}
+	false
+}
+
+/// [ProofProvider] which fans a request out to every one of a list of providers unconditionally
+/// -- lets operators combine the DHT and the direct request-response protocol instead of picking
+/// just one. There's no success/failure signal on [ProofProvider] to short-circuit on, so this
+/// always asks (or tells) all of them; each provider is still independently responsible for
+/// ignoring a block it already has nothing to say about.
+pub struct CompositeProofProvider {
+	providers: Vec<Arc<dyn ProofProvider + Send + Sync>>,
+}
+impl CompositeProofProvider {
+	/// Create a new [CompositeProofProvider] fanning out to `providers`
+	pub fn new(providers: Vec<Arc<dyn ProofProvider + Send + Sync>>) -> Self {
+		Self { providers }
+	}
+}
+#[async_trait]
+impl ProofProvider for CompositeProofProvider {
+	async fn request_proofs(&self, block_hash: H256, events: &[H256]) {
+		for provider in &self.providers {
+			provider.request_proofs(block_hash, events).await;
+		}
+	}
+	async fn provide_proofs(&self, block_hash: H256, proofs: ProofsMap) {
+		for provider in &self.providers {
+			provider.provide_proofs(block_hash, proofs.clone()).await;
+		}
+	}
+}
+
+/// [ProofProvider] which stores and retreives proofs through the Kademlia DHT: publishing puts a
+/// block's [ProofsMap] under a key derived from its hash, and fetching issues a `get_value` whose
+/// result arrives later as a `DhtEvent::ValueFound`.
+pub struct DhtProofProvider {
+	network_service: Arc<Mutex<Option<Arc<NetworkService<Block, H256>>>>>,
+}
+impl DhtProofProvider {
+	/// Create a new [DhtProofProvider], not yet bound to a [NetworkService]
+	pub fn new() -> Self {
+		Self { network_service: Arc::new(Mutex::new(None)) }
+	}
+	/// handle shared with the dht event loop so the network service can be set once it becomes
+	/// available
+	pub fn network_service_handle(&self) -> Arc<Mutex<Option<Arc<NetworkService<Block, H256>>>>> {
+		self.network_service.clone()
+	}
+	/// record the [NetworkService] once the node's networking stack is up, so subsequent
+	/// `request_proofs`/`provide_proofs` calls can reach the DHT
+	pub async fn set_network_service(&self, network_service: Arc<NetworkService<Block, H256>>) {
+		*self.network_service.lock().await = Some(network_service);
+	}
+}
+impl Default for DhtProofProvider {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+#[async_trait]
+impl ProofProvider for DhtProofProvider {
+	async fn request_proofs(&self, block_hash: H256, _events: &[H256]) {
+		if let Some(dht) = &*self.network_service.lock().await {
+			let key = KademliaKey::new(block_hash.as_bytes());
+			dht.get_value(&key);
+			log::info!("request sent to the dht to retreive proofs");
+		} else {
+			log::error!("cant retreive block proofs, dht currently unavailable");
+		}
+	}
+	async fn provide_proofs(&self, block_hash: H256, proofs: ProofsMap) {
+		if let Some(dht) = &*self.network_service.lock().await {
+			let key = KademliaKey::new(block_hash.as_bytes());
+			match bincode::serialize(&proofs) {
+				Ok(value) => dht.put_value(key, value),
+				Err(e) => log::error!("cant serialize proofs:{}", e),
+			}
+		} else {
+			log::error!("cant provide block proofs, dht currently unavailable");
+		}
+	}
+}
+
+/// [ProofProvider] which asks peers directly for a block's proofs over a dedicated
+/// request-response substream, giving a deterministic fallback for when the DHT has no value
+/// (e.g. it hasn't propagated yet, or is "currently unavailable"). Nothing in this node wiring
+/// feeds block-announcement events into [Self::record_announcer] yet, so in practice every
+/// `request_proofs` call broadcasts to every currently connected peer in turn, stopping at the
+/// first that responds with verified proofs; `record_announcer` is kept as the entry point for a
+/// future, more targeted path once that wiring exists.
+pub struct RequestResponseProofProvider {
+	network_service: Arc<NetworkService<Block, H256>>,
+	announcers: Arc<Mutex<HashMap<H256, PeerId>>>,
+	deffered_blocks: Arc<Mutex<HashMap<H256, (NumberFor<Block>, Vec<H256>)>>>,
+	client: Arc<crate::configs::FullClient>,
+	event_proofs: Arc<dyn crate::proofs::EventProofs + Send + Sync>,
+}
+impl RequestResponseProofProvider {
+	/// Create a new [RequestResponseProofProvider] bound to `network_service`, sharing the
+	/// deferred-block bookkeeping of the
+	/// [DefferedBlocks](crate::services::witness_block_import::DefferedBlocks) it serves
+	pub fn new(
+		network_service: Arc<NetworkService<Block, H256>>,
+		deffered_blocks: Arc<Mutex<HashMap<H256, (NumberFor<Block>, Vec<H256>)>>>,
+		client: Arc<crate::configs::FullClient>,
+		event_proofs: Arc<dyn crate::proofs::EventProofs + Send + Sync>,
+	) -> Self {
+		Self {
+			network_service,
+			announcers: Arc::new(Mutex::new(HashMap::new())),
+			deffered_blocks,
+			client,
+			event_proofs,
+		}
+	}
+	/// build the [ProtocolConfig] to register with the network behaviour at service construction,
+	/// together with the channel incoming requests should be served from via
+	/// [Self::spawn_responder]
+	pub fn protocol_config() -> (ProtocolConfig, async_channel::Receiver<IncomingRequest>) {
+		let (tx, rx) = async_channel::bounded(64);
+		let config = ProtocolConfig {
+			name: Cow::Borrowed(PROOF_REQUEST_PROTOCOL_NAME),
+			fallback_names: Vec::new(),
+			max_request_size: 1024,
+			max_response_size: 16 * 1024 * 1024,
+			request_timeout: Duration::from_secs(10),
+			inbound_queue: Some(tx),
+		};
+		(config, rx)
+	}
+	/// remember which peer announced `block_hash`, so a later [Self::request_proofs] for that
+	/// block knows who to ask instead of broadcasting to every connected peer. Not yet called
+	/// anywhere in this node's wiring -- see the struct-level doc comment.
+	pub async fn record_announcer(&self, block_hash: H256, peer: PeerId) {
+		self.announcers.lock().await.insert(block_hash, peer);
+	}
+	/// serve incoming proof requests from `incoming_requests` against this node's own
+	/// [EventProofs](crate::proofs::EventProofs) store
+	pub fn spawn_responder(
+		incoming_requests: async_channel::Receiver<IncomingRequest>,
+		event_proofs: Arc<dyn crate::proofs::EventProofs + Send + Sync>,
+	) {
+		tokio::spawn(async move {
+			while let Ok(request) = incoming_requests.recv().await {
+				let IncomingRequest { payload, pending_response, .. } = request;
+				let response = bincode::deserialize::<H256>(&payload)
+					.ok()
+					.and_then(|block_hash| event_proofs.get_block_proofs(&block_hash).ok())
+					.and_then(|proofs| bincode::serialize(&proofs).ok())
+					.unwrap_or_default();
+				let _ = pending_response.send(OutgoingResponse {
+					result: Ok(response),
+					reputation_changes: Vec::new(),
+					sent_feedback: None,
+				});
+			}
+		});
+	}
+}
+impl RequestResponseProofProvider {
+	/// candidate peers to ask for `block_hash`'s proofs: the peer that announced it if one is on
+	/// record, otherwise every currently connected peer, since we've got nothing better to go on
+	async fn candidate_peers(&self, block_hash: H256) -> Vec<PeerId> {
+		let announcer = self.announcers.lock().await.get(&block_hash).copied();
+		let connected = self.network_service.peers_debug_info().into_iter().map(|(peer, _)| peer);
+		candidate_peers_from(announcer, connected)
+	}
+	/// ask a single `peer` for `block_hash`'s proofs, verify and store them on success. Returns
+	/// whether the block is now fully proven, so [Self::request_proofs] can stop trying peers.
+	async fn request_from_peer(&self, block_hash: H256, peer: PeerId) -> bool {
+		let payload = match bincode::serialize(&block_hash) {
+			Ok(payload) => payload,
+			Err(e) => {
+				log::error!("cant serialize proof request:{}", e);
+				return false
+			},
+		};
+		let (tx, rx) = oneshot::channel();
+		self.network_service.start_request(
+			peer,
+			PROOF_REQUEST_PROTOCOL_NAME.into(),
+			payload,
+			tx,
+			IfDisconnected::ImmediateError,
+		);
+		match rx.await {
+			Ok(Ok((response, _protocol))) => match bincode::deserialize::<ProofsMap>(&response) {
+				Ok(proofs) => {
+					let mut deffered = self.deffered_blocks.lock().await;
+					if let Some((block_number, unwitnessed_events)) = deffered.get(&block_hash) {
+						match super::witness_block_import::DefferedBlocks::verify_proofs(
+							&proofs,
+							unwitnessed_events,
+							*block_number,
+							self.client.clone(),
+						) {
+							Ok(true) => {
+								log::info!(
+									"💡 Retreived all event proofs of block {} from {}",
+									block_hash,
+									peer
+								);
+								self.event_proofs.add_events_proofs(proofs).ok();
+								self.event_proofs.remove_deffered_block(&block_hash).ok();
+								deffered.remove(&block_hash);
+								return true
+							},
+							Ok(false) => log::error!("proofs from {} failed verification", peer),
+							Err(e) => log::error!("error verifying proofs from {}:{}", peer, e),
+						}
+					}
+				},
+				Err(_) => log::error!("failed deserializing proofs received from {}", peer),
+			},
+			Ok(Err(e)) => log::error!("proof request to {} failed:{}", peer, e),
+			Err(_) => log::error!("proof request to {} was cancelled", peer),
+		}
+		false
+	}
+}
+#[async_trait]
+impl ProofProvider for RequestResponseProofProvider {
+	async fn request_proofs(&self, block_hash: H256, _events: &[H256]) {
+		let peers = self.candidate_peers(block_hash).await;
+		if peers.is_empty() {
+			log::error!("no peers available to request proofs of block {} from", block_hash);
+			return
+		}
+		try_peers_until_success(peers, |peer| self.request_from_peer(block_hash, peer)).await;
+	}
+	async fn provide_proofs(&self, _block_hash: H256, _proofs: ProofsMap) {
+		// nothing to publish: requests are served on demand by `spawn_responder` straight from
+		// this node's `EventProofs` store, there's no separate publish step like with the DHT
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn candidate_peers_prefers_the_recorded_announcer() {
+		let announcer = PeerId::random();
+		let connected = vec![PeerId::random(), PeerId::random()];
+		assert_eq!(candidate_peers_from(Some(announcer), connected.into_iter()), vec![announcer]);
+	}
+
+	#[test]
+	fn candidate_peers_falls_back_to_every_connected_peer() {
+		let connected = vec![PeerId::random(), PeerId::random()];
+		assert_eq!(candidate_peers_from(None, connected.clone().into_iter()), connected);
+	}
+
+	#[tokio::test]
+	async fn try_peers_until_success_stops_at_the_first_success() {
+		let peers = vec![PeerId::random(), PeerId::random(), PeerId::random()];
+		let succeeding_peer = peers[1];
+		let tried = std::sync::Arc::new(Mutex::new(Vec::new()));
+		let stopped_early = try_peers_until_success(peers.clone(), |peer| {
+			let tried = tried.clone();
+			async move {
+				tried.lock().await.push(peer);
+				peer == succeeding_peer
+			}
+		})
+		.await;
+		assert!(stopped_early);
+		assert_eq!(*tried.lock().await, vec![peers[0], peers[1]], "must not try peers after a success");
+	}
+
+	#[tokio::test]
+	async fn try_peers_until_success_exhausts_all_peers_when_none_succeed() {
+		let peers = vec![PeerId::random(), PeerId::random()];
+		let tried = std::sync::Arc::new(Mutex::new(Vec::new()));
+		let found = try_peers_until_success(peers.clone(), |peer| {
+			let tried = tried.clone();
+			async move {
+				tried.lock().await.push(peer);
+				false
+			}
+		})
+		.await;
+		assert!(!found);
+		assert_eq!(*tried.lock().await, peers, "must try every peer when none succeed");
+	}
+}
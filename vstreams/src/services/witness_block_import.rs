@@ -3,17 +3,17 @@
 use crate::configs::FullClient;
 use crate::{errors::Error,
 		proofs::{EventProofs, ProofsMap},
-		services::events::EventService,
+		services::{events::EventService, proof_provider::ProofProvider},
 };
 use futures::StreamExt;
 use node_runtime::{self, opaque::Block, pallet_validated_streams::ExtrinsicDetails};
 use sc_consensus::{BlockCheckParams, BlockImport, BlockImportParams, ImportResult};
 pub use sc_executor::NativeElseWasmExecutor;
-use sc_network::{DhtEvent, Event, KademliaKey, NetworkDHTProvider, NetworkService};
+use sc_network::{DhtEvent, Event, KademliaKey, NetworkService};
 use sc_network_common::service::NetworkEventStream;
 use sp_api::ProvideRuntimeApi;
 use sp_blockchain::well_known_cache_keys;
-use sp_consensus::Error as ConsensusError;
+use sp_consensus::{BlockOrigin, Error as ConsensusError, SyncOracle};
 use sp_consensus_aura::AuraApi;
 use sp_core::{
 	sr25519::{Public, Signature},
@@ -22,9 +22,81 @@ use sp_core::{
 use sp_runtime::{
 	app_crypto::{CryptoTypePublicPair, RuntimePublic},
 	generic::BlockId,
+	traits::{NumberFor, One, Saturating},
+	ConsensusEngineId, Justification,
 };
 use std::{collections::HashMap, sync::Arc};
 use tokio::sync::Mutex;
+
+/// [ConsensusEngineId] used to tag the [Justification] carrying a block's event proofs, so that
+/// they travel with the block itself instead of relying on a DHT round-trip after import.
+pub const VALIDATED_STREAMS_ENGINE_ID: ConsensusEngineId = *b"vstr";
+
+/// bincode-encode `proofs` into a [Justification] tagged with [VALIDATED_STREAMS_ENGINE_ID], for
+/// the authoring side to attach to a produced block.
+pub fn proofs_to_justification(proofs: &ProofsMap) -> Result<Justification, Error> {
+	let encoded = bincode::serialize(proofs).map_err(|e| Error::Other(e.to_string()))?;
+	Ok((VALIDATED_STREAMS_ENGINE_ID, encoded))
+}
+/// whether `origin` or the node's own sync state should skip witness-gating for a block: blocks
+/// pulled in during initial sync (or replayed from a file) were authored long ago and their gossip
+/// proofs are very likely gone from the DHT by now, and while a major sync is in progress there's
+/// no point gating freshly-gossiped blocks on proofs either, since the node is busy catching up on
+/// historical ones. Split out of [WitnessBlockImport::import_block] so the decision can be tested
+/// against every [BlockOrigin] without a [FullClient] or a real [SyncOracle].
+fn should_bypass_witness_gating(
+	origin: BlockOrigin,
+	sync_oracle: &(dyn SyncOracle + Send + Sync),
+) -> bool {
+	matches!(origin, BlockOrigin::NetworkInitialSync | BlockOrigin::File) ||
+		sync_oracle.is_major_syncing()
+}
+
+/// check that `proofs` gathers enough valid signatures for `unwitnessed_events` from `authorities`,
+/// the authority set active when the deferred block in question was authored. Split out of
+/// [DefferedBlocks::verify_proofs] so the signature/threshold logic can be exercised directly
+/// against a synthetic authority set, without a [FullClient] to fetch one from.
+fn verify_proofs_against_authorities(
+	proofs: &ProofsMap,
+	unwitnessed_events: &[H256],
+	authorities: &[CryptoTypePublicPair],
+) -> Result<bool, Error> {
+	let target = (2 * ((authorities.len() - 1) / 3) + 1) as u16;
+	for event in unwitnessed_events {
+		let mut proof_count = 0;
+		if proofs.contains_key(event) {
+			let proof =
+				proofs.get(event).ok_or(Error::Other("Empty ProofsMap given".to_string()))?;
+			for key in proof.keys() {
+				if !authorities.contains(key) {
+					log::error!("received an event proof from an Unkown validator");
+					return Ok(false)
+				}
+			}
+			for (key, sig) in proof {
+				let signature = Signature::from_slice(sig.as_slice())
+					.ok_or(Error::Other("bad signature".to_string()))?;
+				let pubkey = Public::from_slice(key.1.as_slice()).map_err(|_| {
+					log::error!("bad public key provided for proof");
+					Error::Other("bad public key".to_string())
+				})?;
+				if !pubkey.verify(&event, &signature) {
+					log::error!("received faulty signature");
+					return Ok(false)
+				}
+				proof_count += 1;
+			}
+			if proof_count < target {
+				log::error!("Not Enough Proofs for event {:?}", event);
+				return Ok(false)
+			}
+		} else {
+			log::error!("didn't receive proof for event {:?}", event);
+			return Ok(false)
+		}
+	}
+	Ok(true)
+}
 /// Wrapper around a [sc_consensus::BlockImport] which waits for all events to be witnessed in an
 /// [EventProofs] instance before forwarding the block to the next import -- in effect preventing
 /// the finalization for blocks that lack sufficient signatures from the gossip.
@@ -37,21 +109,85 @@ where
 	client: Arc<FullClient>,
 	event_proofs: Arc<dyn EventProofs + Send + Sync>,
 	deffered_blocks: Arc<DefferedBlocks>,
+	/// used to tell whether the node is still catching up with the network, so that blocks
+	/// imported during a major sync aren't held back waiting for gossip proofs that may no
+	/// longer be available
+	sync_oracle: Arc<dyn SyncOracle + Send + Sync>,
 }
 /// conatiner and manager of deffered blocks
 pub struct DefferedBlocks {
 	/// list of deffered block and their corresponding unwitnessed_event
-	pub inner: Arc<Mutex<HashMap<H256, Vec<H256>>>>,
-	/// provides access to the distributed hash table across all instances of the witness block
-	/// import
-	pub network_service: Arc<Mutex<Option<Arc<NetworkService<Block, H256>>>>>,
+	pub inner: Arc<Mutex<HashMap<H256, (NumberFor<Block>, Vec<H256>)>>>,
+	/// transport(s) used to request missing proofs from, and advertise this node's own proofs to,
+	/// the rest of the network -- e.g. the DHT, a direct request-response protocol, or both
+	/// stacked together
+	pub proof_provider: Arc<dyn ProofProvider + Send + Sync>,
+	/// backing store shared with the [EventProofs] used to persist deffered blocks so that a
+	/// restart doesn't lose track of blocks still awaiting proofs
+	event_proofs: Arc<dyn EventProofs + Send + Sync>,
 }
 impl DefferedBlocks {
+	/// Create a new [DefferedBlocks] backed by `proof_provider`, restoring any block left pending
+	/// across a restart from `event_proofs` and re-requesting their proofs
+	pub async fn new(
+		proof_provider: Arc<dyn ProofProvider + Send + Sync>,
+		event_proofs: Arc<dyn EventProofs + Send + Sync>,
+	) -> Self {
+		let deffered_blocks =
+			Self { inner: Arc::new(Mutex::new(HashMap::new())), proof_provider, event_proofs };
+		deffered_blocks.load_pending().await;
+		deffered_blocks
+	}
+	/// reload blocks that were still awaiting proofs when the node last shut down, and
+	/// re-request their proofs from the network
+	async fn load_pending(&self) {
+		let pending = match self.event_proofs.get_deffered_blocks() {
+			Ok(pending) => pending,
+			Err(e) => {
+				log::error!("failed loading persisted deffered blocks:{}", e);
+				return
+			},
+		};
+		let mut inner = self.inner.lock().await;
+		for (block_hash, (block_number, unwitnessed_events)) in pending {
+			log::info!(
+				"⏭️  Restored deffered block {} containing {} unwitnessed events",
+				block_hash,
+				unwitnessed_events.len()
+			);
+			self.proof_provider.request_proofs(block_hash, &unwitnessed_events).await;
+			inner.insert(block_hash, (block_number, unwitnessed_events));
+		}
+	}
+	/// periodically re-request proofs for every block still pending. A one-shot request (made
+	/// when the block was first deferred, or when it's reloaded in [Self::load_pending]) can race
+	/// the network/DHT service coming up this early in node startup and be lost for good, so
+	/// pending blocks need to keep being retried until their proofs actually show up.
+	pub fn spawn_retry_loop(self: Arc<Self>) {
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
+			loop {
+				interval.tick().await;
+				let pending: Vec<(H256, Vec<H256>)> = self
+					.inner
+					.lock()
+					.await
+					.iter()
+					.map(|(block_hash, (_, unwitnessed_events))| {
+						(*block_hash, unwitnessed_events.clone())
+					})
+					.collect();
+				for (block_hash, unwitnessed_events) in pending {
+					self.proof_provider.request_proofs(block_hash, &unwitnessed_events).await;
+				}
+			}
+		});
+	}
 	/// handles incoming dht events and set the network service
 	/// for all instances of the witness block import
 	pub async fn handle_dht_events(
 		dht: Arc<Mutex<Option<Arc<NetworkService<Block, H256>>>>>,
-		inner_blocks: Arc<Mutex<HashMap<H256, Vec<H256>>>>,
+		inner_blocks: Arc<Mutex<HashMap<H256, (NumberFor<Block>, Vec<H256>)>>>,
 		network_service: Arc<NetworkService<Block, H256>>,
 		client: Arc<FullClient>,
 		event_proofs: Arc<dyn EventProofs + Send + Sync>,
@@ -79,7 +215,7 @@ impl DefferedBlocks {
 	}
 	async fn handle_found_proofs(
 		values: Vec<(KademliaKey, Vec<u8>)>,
-		deffered_blocks: Arc<Mutex<HashMap<H256, Vec<H256>>>>,
+		deffered_blocks: Arc<Mutex<HashMap<H256, (NumberFor<Block>, Vec<H256>)>>>,
 		client: Arc<FullClient>,
 		event_proofs: Arc<dyn EventProofs + Send + Sync>,
 	) {
@@ -91,16 +227,21 @@ impl DefferedBlocks {
 				let desrialized_key = H256::from_slice(key_vec.as_slice());
 				if inner.contains_key(&desrialized_key) {
 					if let Ok(proofs) = bincode::deserialize::<ProofsMap>(&value) {
-						let unwitnessed_events = inner.get(&desrialized_key).unwrap();
-						if let Ok(result) =
-							Self::verify_proofs(&proofs, &unwitnessed_events, client.clone())
-						{
+						let (block_number, unwitnessed_events) =
+							inner.get(&desrialized_key).unwrap();
+						if let Ok(result) = Self::verify_proofs(
+							&proofs,
+							unwitnessed_events,
+							*block_number,
+							client.clone(),
+						) {
 							if result {
 								log::info!(
 									"💡 Retreived all event proofs of block {}",
 									desrialized_key
 								);
 								event_proofs.add_events_proofs(proofs).ok();
+								event_proofs.remove_deffered_block(&desrialized_key).ok();
 								inner.remove(&desrialized_key);
 							}
 						}
@@ -113,12 +254,19 @@ impl DefferedBlocks {
 			}
 		}
 	}
-	fn verify_proofs(
+	/// verify that `proofs` gathers enough valid signatures for `unwitnessed_events`, from the
+	/// authority set that was active when `block_number` was authored -- *not* the chain's
+	/// current best block, since a deferred block's signatures were produced by whichever set was
+	/// active back then, and validator rotation would otherwise make this reject valid proofs (or
+	/// accept invalid ones). The set is read from `block_number`'s *parent*, since the deferred
+	/// block itself isn't committed to the backend yet and its own height can't be queried.
+	pub(crate) fn verify_proofs(
 		proofs: &ProofsMap,
 		unwitnessed_events: &[H256],
+		block_number: NumberFor<Block>,
 		client: Arc<FullClient>,
 	) -> Result<bool, Error> {
-		let block_id = BlockId::Number(client.chain_info().best_number);
+		let block_id = BlockId::Number(block_number.saturating_sub(One::one()));
 		let authorities: Vec<CryptoTypePublicPair> = client
 			.runtime_api()
 			.authorities(&block_id)
@@ -126,58 +274,27 @@ impl DefferedBlocks {
 			.iter()
 			.map(CryptoTypePublicPair::from)
 			.collect();
-		let target = (2 * ((authorities.len() - 1) / 3) + 1) as u16;
-		for event in unwitnessed_events {
-			let mut proof_count = 0;
-			if proofs.contains_key(event) {
-				let proof =
-					proofs.get(event).ok_or(Error::Other("Empty ProofsMap given".to_string()))?;
-				for key in proof.keys() {
-					if !authorities.contains(key) {
-						log::error!("received an event proof from an Unkown validator");
-						return Ok(false)
-					}
-				}
-				for (key, sig) in proof {
-					let signature = Signature::from_slice(sig.as_slice())
-						.ok_or(Error::Other("bad signature".to_string()))?;
-					let pubkey = Public::from_slice(key.1.as_slice()).map_err(|_| {
-						log::error!("bad public key provided for proof");
-						Error::Other("bad public key".to_string())
-					})?;
-					if !pubkey.verify(&event, &signature) {
-						log::error!("received faulty signature");
-						return Ok(false)
-					}
-					proof_count += 1;
-				}
-				if proof_count < target {
-					log::error!("Not Enough Proofs for event {:?}", event);
-					return Ok(false)
-				}
-			} else {
-				log::error!("didn't receive proof for event {:?}", event);
-				return Ok(false)
-			}
-		}
-		return Ok(true)
+		verify_proofs_against_authorities(proofs, unwitnessed_events, &authorities)
 	}
-	async fn deffer_block(&self, block_hash: H256, unwitnessed_events: &[H256]) {
-		let key = KademliaKey::new(&block_hash.as_bytes());
+	async fn deffer_block(
+		&self,
+		block_hash: H256,
+		block_number: NumberFor<Block>,
+		unwitnessed_events: &[H256],
+	) {
 		let mut inner = self.inner.lock().await;
-		if let Some(dht) = &*self.network_service.lock().await {
-			if let None = inner.insert(block_hash, unwitnessed_events.into()) {
-				log::info!(
-					"⏭️  Deffered Block {} containing {} unwitnessed events",
-					block_hash,
-					unwitnessed_events.len()
-				);
-			}
-			dht.get_value(&key);
-			log::info!("request sent to the dht to retreive proofs")
-		} else {
-			log::error!("cant retreive block proofs, dht currently unavailable");
+		if let None = inner.insert(block_hash, (block_number, unwitnessed_events.into())) {
+			log::info!(
+				"⏭️  Deffered Block {} containing {} unwitnessed events",
+				block_hash,
+				unwitnessed_events.len()
+			);
+			self.event_proofs
+				.add_deffered_block(&block_hash, block_number, unwitnessed_events)
+				.ok();
 		}
+		drop(inner);
+		self.proof_provider.request_proofs(block_hash, unwitnessed_events).await;
 	}
 }
 
@@ -191,8 +308,10 @@ where
 		client: Arc<FullClient>,
 		event_proofs: Arc<dyn EventProofs + Send + Sync>,
 		deffered_blocks: Arc<DefferedBlocks>,
+		sync_oracle: Arc<dyn SyncOracle + Send + Sync>,
 	) -> Self {
-		Self { parent_block_import, client, event_proofs, deffered_blocks }
+		deffered_blocks.clone().spawn_retry_loop();
+		Self { parent_block_import, client, event_proofs, deffered_blocks, sync_oracle }
 	}
 }
 #[async_trait::async_trait]
@@ -219,7 +338,7 @@ where
 		block: BlockImportParams<Block, Self::Transaction>,
 		cache: HashMap<well_known_cache_keys::Id, Vec<u8>>,
 	) -> Result<ImportResult, Self::Error> {
-		if let Some(block_extrinsics) = &block.body {
+		let event_ids = if let Some(block_extrinsics) = &block.body {
 			let block_id = BlockId::Number(self.client.chain_info().best_number);
 			let event_ids = self
 				.client
@@ -227,6 +346,55 @@ where
 				.get_extrinsic_ids(&block_id, block_extrinsics)
 				.ok()
 				.unwrap_or_default();
+			// proofs travelling with the block as a justification are both cheaper and more
+			// reliable than an async DHT round-trip, so they're recorded unconditionally -- even
+			// for a block about to skip witness-gating below, since a synced block's justification
+			// is the only place its event proofs will ever come from again.
+			if let Some(proofs) = block
+				.justifications
+				.as_ref()
+				.and_then(|justifications| justifications.get(VALIDATED_STREAMS_ENGINE_ID))
+				.and_then(|encoded| bincode::deserialize::<ProofsMap>(encoded).ok())
+			{
+				match DefferedBlocks::verify_proofs(
+					&proofs,
+					&event_ids,
+					*block.header.number(),
+					self.client.clone(),
+				) {
+					Ok(true) => {
+						self.event_proofs.add_events_proofs(proofs).ok();
+						// index these events against the block so a later request-response
+						// lookup (spawn_responder/get_block_proofs) can re-serve them to a peer
+						// still syncing, same as the gossip-witnessed path does below.
+						self.provide_block_proofs(block.header.hash(), &event_ids).await;
+					},
+					Ok(false) => log::error!("justification proofs failed verification"),
+					Err(e) => log::error!("error verifying justification proofs:{}", e),
+				}
+			}
+			Some(event_ids)
+		} else {
+			None
+		};
+		// blocks pulled in during initial sync (or replayed from a file) were authored long ago
+		// and their gossip proofs are very likely gone from the DHT by now; witnessing every one
+		// of them would stall a fresh node forever, so only freshly gossiped/authored blocks are
+		// gated behind `EventService::verify_events_validity`. Any proofs the block's own
+		// justification carried were already recorded above, regardless of this bypass.
+		if should_bypass_witness_gating(block.origin, self.sync_oracle.as_ref()) {
+			log::info!(
+				"⏩ skipping witness-gating for block {} imported during sync (origin: {:?})",
+				block.header.hash(),
+				block.origin
+			);
+			return self
+				.parent_block_import
+				.import_block(block, cache)
+				.await
+				.map_err(|e| ConsensusError::ClientImport(format!("{}", e)))
+		}
+		if let Some(event_ids) = event_ids {
 			match EventService::verify_events_validity(
 				self.client.clone(),
 				self.event_proofs.clone(),
@@ -235,7 +403,11 @@ where
 				Ok(unwitnessed_ids) =>
 					if !unwitnessed_ids.is_empty() {
 						self.deffered_blocks
-							.deffer_block(block.header.hash(), &unwitnessed_ids)
+							.deffer_block(
+								block.header.hash(),
+								*block.header.number(),
+								&unwitnessed_ids,
+							)
 							.await;
 						return Err(ConsensusError::ClientImport(format!(
 							"block contains unwitnessed events"
@@ -246,8 +418,7 @@ where
 							self.parent_block_import.import_block(block, cache).await;
 						match parent_result {
 							Ok(result) => {
-								let dht = self.deffered_blocks.network_service.clone();
-								self.provide_block_proofs(dht, block_hash, &event_ids).await;
+								self.provide_block_proofs(block_hash, &event_ids).await;
 								log::info!("📥 Block {} Imported", block_hash);
 								return Ok(result)
 							},
@@ -272,27 +443,95 @@ impl<I> WitnessBlockImport<I>
 where
 	I: sc_consensus::BlockImport<Block> + Sync,
 {
-	async fn provide_block_proofs(
-		&self,
-		network_service: Arc<Mutex<Option<Arc<NetworkService<Block, H256>>>>>,
-		block_hash: H256,
-		event_ids: &[H256],
-	) {
-		if let Some(dht) = &*network_service.lock().await {
-			match self.event_proofs.get_events_proofs(event_ids) {
-				Ok(proofs) => {
-					let key = KademliaKey::new(&block_hash.as_bytes());
-					match bincode::serialize(&proofs) {
-						Ok(value) => {
-							dht.put_value(key.clone(), value);
-						},
-						Err(e) => log::error!("cant serialize proofs:{}", e),
-					}
-				},
-				Err(_) => {},
+	async fn provide_block_proofs(&self, block_hash: H256, event_ids: &[H256]) {
+		self.event_proofs.record_block_events(&block_hash, event_ids).ok();
+		if let Ok(proofs) = self.event_proofs.get_events_proofs(event_ids) {
+			self.deffered_blocks.proof_provider.provide_proofs(block_hash, proofs).await;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_core::Pair;
+
+	fn signed_proof(signers: &[sp_core::sr25519::Pair], event: H256) -> ProofsMap {
+		let mut proof = HashMap::new();
+		for pair in signers {
+			let public: Public = pair.public();
+			let signature = pair.sign(event.as_bytes());
+			proof.insert(CryptoTypePublicPair::from(&public), signature.as_slice().to_vec());
+		}
+		HashMap::from([(event, proof)])
+	}
+
+	// regression test for the chunk0-5 fix: proofs must be checked against the authority set
+	// that was active when they were produced, not whichever set happens to be current. Proofs
+	// signed by the pre-rotation set must verify against that set and must not verify against
+	// the post-rotation set.
+	#[test]
+	fn rejects_proofs_from_a_since_rotated_out_authority_set() {
+		let event = H256::repeat_byte(7);
+		let pre_rotation: Vec<sp_core::sr25519::Pair> =
+			(0..4).map(|i| sp_core::sr25519::Pair::from_seed(&[i; 32])).collect();
+		let post_rotation: Vec<sp_core::sr25519::Pair> =
+			(4..8).map(|i| sp_core::sr25519::Pair::from_seed(&[i; 32])).collect();
+
+		let proofs = signed_proof(&pre_rotation, event);
+
+		let pre_rotation_keys: Vec<CryptoTypePublicPair> =
+			pre_rotation.iter().map(|pair| CryptoTypePublicPair::from(&pair.public())).collect();
+		let post_rotation_keys: Vec<CryptoTypePublicPair> =
+			post_rotation.iter().map(|pair| CryptoTypePublicPair::from(&pair.public())).collect();
+
+		assert!(
+			verify_proofs_against_authorities(&proofs, &[event], &pre_rotation_keys).unwrap(),
+			"proofs signed by the set active at the deferred block's height must verify"
+		);
+		assert!(
+			!verify_proofs_against_authorities(&proofs, &[event], &post_rotation_keys).unwrap(),
+			"proofs signed by an authority set that has since rotated out must not verify"
+		);
+	}
+
+	struct StubSyncOracle {
+		is_major_syncing: bool,
+	}
+	impl SyncOracle for StubSyncOracle {
+		fn is_major_syncing(&self) -> bool {
+			self.is_major_syncing
+		}
+		fn is_offline(&self) -> bool {
+			false
+		}
+	}
+
+	// regression test for the chunk0-1 sync bypass: it must trigger for every origin a synced-up
+	// node replays history through, and otherwise only when the node itself reports a major sync
+	// in progress, so a future refactor can't silently widen it to freshly-gossiped blocks.
+	#[test]
+	fn bypasses_witness_gating_only_for_sync_origins_or_a_major_sync() {
+		let origins = [
+			BlockOrigin::Genesis,
+			BlockOrigin::NetworkInitialSync,
+			BlockOrigin::NetworkBroadcast,
+			BlockOrigin::ConsensusBroadcast,
+			BlockOrigin::Own,
+			BlockOrigin::File,
+		];
+		for origin in origins {
+			let is_ancient = matches!(origin, BlockOrigin::NetworkInitialSync | BlockOrigin::File);
+			for is_major_syncing in [false, true] {
+				let sync_oracle = StubSyncOracle { is_major_syncing };
+				assert_eq!(
+					should_bypass_witness_gating(origin, &sync_oracle),
+					is_ancient || is_major_syncing,
+					"origin {:?}, is_major_syncing {}",
+					origin,
+					is_major_syncing
+				);
 			}
-		} else {
-			log::error!("cant provide block proofs, dht currently unavailable");
 		}
 	}
 }
\ No newline at end of file